@@ -0,0 +1,196 @@
+//! Pieces of the HTTP/1.1 message syntax (RFC 7230).
+use std::io::{IoResult, standard_error, OtherIoError};
+
+/// Carriage return
+pub static CR: u8 = b'\r';
+
+/// Line feed
+pub static LF: u8 = b'\n';
+
+/// The octets used to end a line in an HTTP message.
+pub static LINE_ENDING: &'static [u8] = b"\r\n";
+
+/// Wraps a `Writer`, applying the body-framing rules decided by a
+/// `Response`'s headers, so callers can just `write()` and never think
+/// about chunking, sizing, or connection-close semantics themselves.
+pub enum HttpWriter<W> {
+    /// A writer that writes bytes right through, for a body delimited
+    /// by the connection closing at EOF.
+    ThroughWriter(W),
+    /// A writer that wraps each write in a chunk, per
+    /// `Transfer-Encoding: chunked`.
+    ChunkedWriter(W),
+    /// A writer that counts down a declared `Content-Length`, erroring
+    /// if the handler tries to write past it.
+    SizedWriter(W, u64),
+    /// A writer that refuses any body at all, erroring on a non-empty
+    /// write, for status classes that forbid one (204, 304, 1xx).
+    EmptyWriter(W),
+    /// A writer that silently discards every write, for a HEAD response:
+    /// unlike `EmptyWriter`, a handler writing a normal body isn't doing
+    /// anything wrong, so the bytes are just thrown away instead of
+    /// erroring.
+    DiscardWriter(W),
+}
+
+impl<W: Writer> HttpWriter<W> {
+    /// Unwraps this `HttpWriter`, returning the underlying Writer.
+    pub fn unwrap(self) -> W {
+        match self {
+            HttpWriter::ThroughWriter(w) => w,
+            HttpWriter::ChunkedWriter(w) => w,
+            HttpWriter::SizedWriter(w, _) => w,
+            HttpWriter::EmptyWriter(w) => w,
+            HttpWriter::DiscardWriter(w) => w,
+        }
+    }
+
+    /// Writes whatever trailing bytes finish this framing, then returns
+    /// the underlying Writer: the `0\r\n\r\n` terminator for chunked
+    /// bodies, or zero-fill padding up to the declared `Content-Length`
+    /// for a `SizedWriter` a handler finished writing to early. Without
+    /// the padding, a handler that wrote less than it promised would
+    /// leave the client hanging for bytes that will never arrive.
+    pub fn end(mut self) -> IoResult<W> {
+        match self {
+            HttpWriter::ChunkedWriter(ref mut w) => try!(w.write(b"0\r\n\r\n")),
+            HttpWriter::SizedWriter(ref mut w, ref mut remaining) => {
+                let zeros = [0u8, ..4096];
+                while *remaining > 0 {
+                    let n = ::std::cmp::min(*remaining, zeros.len() as u64) as uint;
+                    try!(w.write(zeros.slice_to(n)));
+                    *remaining -= n as u64;
+                }
+            },
+            HttpWriter::ThroughWriter(..) |
+            HttpWriter::EmptyWriter(..) |
+            HttpWriter::DiscardWriter(..) => {}
+        }
+        Ok(self.unwrap())
+    }
+}
+
+impl<W: Writer> Writer for HttpWriter<W> {
+    fn write(&mut self, msg: &[u8]) -> IoResult<()> {
+        match *self {
+            HttpWriter::ThroughWriter(ref mut w) => w.write(msg),
+            HttpWriter::ChunkedWriter(ref mut w) => {
+                if msg.is_empty() {
+                    // A zero-length chunk is the terminating sequence;
+                    // silently dropping it here keeps a handler's stray
+                    // empty `write()` from closing the body early.
+                    return Ok(());
+                }
+                let chunk_size = msg.len();
+                debug!("chunked write, size = {:x}", chunk_size);
+                try!(write!(w, "{:x}", chunk_size));
+                try!(w.write(LINE_ENDING));
+                try!(w.write(msg));
+                w.write(LINE_ENDING)
+            },
+            HttpWriter::SizedWriter(ref mut w, ref mut remaining) => {
+                let len = msg.len() as u64;
+                if len > *remaining {
+                    let truncated = *remaining as uint;
+                    *remaining = 0;
+                    try!(w.write(msg.slice_to(truncated)));
+                    Err(standard_error(OtherIoError))
+                } else {
+                    *remaining -= len;
+                    w.write(msg)
+                }
+            },
+            HttpWriter::EmptyWriter(..) => {
+                if msg.is_empty() {
+                    Ok(())
+                } else {
+                    Err(standard_error(OtherIoError))
+                }
+            },
+            HttpWriter::DiscardWriter(..) => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match *self {
+            HttpWriter::ThroughWriter(ref mut w) => w.flush(),
+            HttpWriter::ChunkedWriter(ref mut w) => w.flush(),
+            HttpWriter::SizedWriter(ref mut w, _) => w.flush(),
+            HttpWriter::EmptyWriter(ref mut w) => w.flush(),
+            HttpWriter::DiscardWriter(ref mut w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::MemWriter;
+    use super::HttpWriter;
+    use super::HttpWriter::{ThroughWriter, ChunkedWriter, SizedWriter, EmptyWriter, DiscardWriter};
+
+    #[test]
+    fn chunked_write_frames_each_chunk_in_hex() {
+        let mut w = ChunkedWriter(MemWriter::new());
+        w.write(b"hello").unwrap();
+        w.write(b"world!").unwrap();
+        let buf = w.end().unwrap().unwrap();
+        assert_eq!(buf.as_slice(), b"5\r\nhello\r\n6\r\nworld!\r\n0\r\n\r\n".as_slice());
+    }
+
+    #[test]
+    fn chunked_write_ignores_empty_writes() {
+        let mut w = ChunkedWriter(MemWriter::new());
+        w.write(b"").unwrap();
+        w.write(b"abc").unwrap();
+        let buf = w.end().unwrap().unwrap();
+        assert_eq!(buf.as_slice(), b"3\r\nabc\r\n0\r\n\r\n".as_slice());
+    }
+
+    #[test]
+    fn sized_write_passes_through_within_the_limit() {
+        let mut w = SizedWriter(MemWriter::new(), 5);
+        w.write(b"hello").unwrap();
+        let buf = w.end().unwrap().unwrap();
+        assert_eq!(buf.as_slice(), b"hello".as_slice());
+    }
+
+    #[test]
+    fn sized_write_errors_and_truncates_past_the_limit() {
+        let mut w = SizedWriter(MemWriter::new(), 3);
+        assert!(w.write(b"hello").is_err());
+        match w {
+            SizedWriter(ref inner, _) => assert_eq!(inner.get_ref(), b"hel"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn sized_write_zero_fills_remaining_bytes_on_end() {
+        let mut w = SizedWriter(MemWriter::new(), 5);
+        w.write(b"ab").unwrap();
+        let buf = w.end().unwrap().unwrap();
+        assert_eq!(buf.as_slice(), b"ab\0\0\0".as_slice());
+    }
+
+    #[test]
+    fn empty_write_rejects_any_body() {
+        let mut w = EmptyWriter(MemWriter::new());
+        assert!(w.write(b"anything").is_err());
+    }
+
+    #[test]
+    fn discard_write_silently_drops_any_body() {
+        let mut w = DiscardWriter(MemWriter::new());
+        w.write(b"anything").unwrap();
+        let buf = w.end().unwrap().unwrap();
+        assert_eq!(buf.as_slice(), b"".as_slice());
+    }
+
+    #[test]
+    fn through_write_passes_bytes_unchanged() {
+        let mut w = ThroughWriter(MemWriter::new());
+        w.write(b"hello").unwrap();
+        let buf = w.end().unwrap().unwrap();
+        assert_eq!(buf.as_slice(), b"hello".as_slice());
+    }
+}
@@ -3,12 +3,15 @@
 //! These are responses sent by a `hyper::Server` to clients, after
 //! receiving a request.
 use std::io::{BufferedWriter, IoResult};
+use std::mem;
 
 use time::now_utc;
 
 use header;
 use header::common;
-use rfc7230::{CR, LF, LINE_ENDING};
+use method::Method;
+use rfc7230::{CR, LF, LINE_ENDING, HttpWriter};
+use rfc7230::HttpWriter::{ThroughWriter, ChunkedWriter, SizedWriter, EmptyWriter, DiscardWriter};
 use status;
 use net::NetworkStream;
 use version;
@@ -29,12 +32,62 @@ impl WriteStatus for Fresh {}
 pub struct Response<W: WriteStatus, S: NetworkStream> {
     /// The HTTP version of this response.
     pub version: version::HttpVersion,
-    // Stream the Response is writing to, not accessible through UnwrittenResponse
-    body: BufferedWriter<S>, // TODO: use a HttpWriter from rfc7230
+    // Stream the Response is writing to, not accessible through UnwrittenResponse.
+    // `None` once the response has been finished (via `end()` or `Drop`) or
+    // its body handed on by `start()` or `deconstruct()`; otherwise `Some`.
+    body: Option<HttpWriter<BufferedWriter<S>>>,
     // The status code for the request.
     status: status::StatusCode,
     // The outgoing headers on this response.
-    headers: header::Headers
+    headers: header::Headers,
+    // Whether the status line and headers have been written yet. Tracked
+    // separately from the `Fresh`/`Streaming` phantom type so `drop` can
+    // tell the two states apart without consuming `self`.
+    wrote_head: bool,
+    // The method of the request this is a response to, so `start()` can
+    // suppress the body on a HEAD response without dropping the headers
+    // that describe the body it would otherwise have sent.
+    method: Method
+}
+
+/// Whether a response with this status must not carry a body at all,
+/// per RFC 7230 section 3.3: 1xx, 204 No Content, and 304 Not Modified.
+fn forbids_body(status: status::StatusCode) -> bool {
+    let code = status as u16;
+    code < 200 || status == status::NoContent || status == status::NotModified
+}
+
+/// Strips any headers describing a body length or encoding. Used
+/// wherever a response ends up carrying no body at all: the 1xx/204/304
+/// status classes in `start()`, and a response dropped before `start()`
+/// was ever called (so whatever the handler set no longer matches the
+/// zero bytes that are actually going out).
+fn strip_body_framing_headers(headers: &mut header::Headers) {
+    headers.remove::<common::ContentLength>();
+    headers.remove::<common::TransferEncoding>();
+}
+
+/// Writes the status line and headers for a response to `body`, adding a
+/// `Date` header if one hasn't been set. Shared by `start()` and the
+/// `Drop` impl, which both need to emit a head from just `&mut self`.
+fn write_head<W: Writer>(version: version::HttpVersion,
+                          status: status::StatusCode,
+                          headers: &mut header::Headers,
+                          body: &mut W) -> IoResult<()> {
+    debug!("writing head: {} {}", version, status);
+    try!(write!(body, "{} {}{}{}", version, status, CR as char, LF as char));
+
+    if !headers.has::<common::Date>() {
+        headers.set(common::Date(now_utc()));
+    }
+
+    for (name, header) in headers.iter() {
+        debug!("headers {}: {}", name, header);
+        try!(write!(body, "{}: {}", name, header));
+        try!(body.write(LINE_ENDING));
+    }
+
+    body.write(LINE_ENDING)
 }
 
 impl<W: WriteStatus, S: NetworkStream> Response<W, S> {
@@ -45,16 +98,31 @@ impl<W: WriteStatus, S: NetworkStream> Response<W, S> {
     /// The headers of this response.
     pub fn headers(&self) -> &header::Headers { &self.headers }
 
+    /// Whether the connection this response was written to can be reused
+    /// for another request, per the RFC 7230 persistence rules: `Http11`
+    /// connections are persistent by default, `Http10` ones are not,
+    /// and either can be overridden with a `Connection` header.
+    pub fn keep_alive(&self) -> bool {
+        match self.headers.get::<common::Connection>() {
+            Some(conn) if conn.0.contains(&common::ConnectionOption::Close) => false,
+            Some(conn) if conn.0.contains(&common::ConnectionOption::KeepAlive) => true,
+            _ => self.version == version::Http11
+        }
+    }
+
     /// Construct a Response from its constituent parts.
     pub fn construct(version: version::HttpVersion,
                      body: BufferedWriter<S>,
                      status: status::StatusCode,
-                     headers: header::Headers) -> Response<Fresh, S> {
+                     headers: header::Headers,
+                     method: Method) -> Response<Fresh, S> {
         Response {
             status: status,
             version: version,
-            body: body,
-            headers: headers
+            body: Some(ThroughWriter(body)),
+            headers: headers,
+            wrote_head: false,
+            method: method
         }
     }
 }
@@ -66,33 +134,68 @@ impl<S: NetworkStream> Response<Fresh, S> {
             status: status::Ok,
             version: version::Http11,
             headers: header::Headers::new(),
-            body: BufferedWriter::new(stream)
+            body: Some(ThroughWriter(BufferedWriter::new(stream))),
+            wrote_head: false,
+            method: Method::Get
         }
     }
 
     /// Consume this Response<Fresh>, writing the Headers and Status and creating a Response<Streaming>
     pub fn start(mut self) -> IoResult<Response<Streaming, S>> {
-        debug!("writing head: {} {}", self.version, self.status);
-        try!(write!(self.body, "{} {}{}{}", self.version, self.status, CR as char, LF as char));
+        let mut body = self.body.take().expect("response already finished");
 
-        if !self.headers.has::<common::Date>() {
-            self.headers.set(common::Date(now_utc()));
-        }
+        let is_head = self.method == Method::Head;
+        let no_body = forbids_body(self.status);
+
+        // Pick the body framing now, before the headers are written, so
+        // the `Transfer-Encoding`/`Connection` headers we add (if any)
+        // are actually sent.
+        let content_length = if no_body {
+            // These status classes can never carry a body, so the
+            // length/encoding headers describing one don't belong either.
+            strip_body_framing_headers(&mut self.headers);
+            None
+        } else {
+            self.headers.get::<common::ContentLength>().map(|cl| cl.0)
+        };
 
-        for (name, header) in self.headers.iter() {
-            debug!("headers {}: {}", name, header);
-            try!(write!(self.body, "{}: {}", name, header));
-            try!(self.body.write(LINE_ENDING));
+        let chunked = !no_body && content_length.is_none() && self.version == version::Http11;
+        if chunked {
+            self.headers.set(common::TransferEncoding(vec![common::Encoding::Chunked]));
+        } else if !no_body && content_length.is_none() {
+            // Neither Content-Length nor chunked framing: the body is
+            // only delimited by the connection closing at EOF, so don't
+            // let the server try to keep this connection alive.
+            self.headers.set(common::Connection(vec![common::ConnectionOption::Close]));
         }
 
-        try!(self.body.write(LINE_ENDING));
+        try!(write_head(self.version, self.status, &mut self.headers, &mut body));
+
+        // A HEAD response still advertises the body it would have sent,
+        // but the body itself must stay empty. Unlike the 204/304/1xx
+        // classes, a handler writing a normal body for HEAD isn't doing
+        // anything wrong (it's the same handler a GET would use), so the
+        // bytes are discarded rather than rejected as an error.
+        let body = if no_body {
+            EmptyWriter(body.unwrap())
+        } else if is_head {
+            DiscardWriter(body.unwrap())
+        } else {
+            match content_length {
+                Some(len) => SizedWriter(body.unwrap(), len),
+                None if chunked => ChunkedWriter(body.unwrap()),
+                None => body,
+            }
+        };
 
         // "copy" to change the phantom type
         Ok(Response {
             version: self.version,
-            body: self.body,
+            body: Some(body),
             status: self.status,
-            headers: self.headers
+            headers: mem::replace(&mut self.headers, header::Headers::new()),
+            wrote_head: true,
+            method: self.method
         })
     }
 
@@ -104,8 +207,22 @@ impl<S: NetworkStream> Response<Fresh, S> {
     pub fn headers_mut(&mut self) -> &mut header::Headers { &mut self.headers }
 
     /// Deconstruct this Response into its constituent parts.
-    pub fn deconstruct(self) -> (version::HttpVersion, BufferedWriter<S>, status::StatusCode, header::Headers) {
-        (self.version, self.body, self.status, self.headers)
+    pub fn deconstruct(mut self) -> (version::HttpVersion, HttpWriter<BufferedWriter<S>>, status::StatusCode, header::Headers) {
+        let body = self.body.take().expect("response already finished");
+        (self.version, body, self.status, mem::replace(&mut self.headers, header::Headers::new()))
+    }
+
+    /// Send a fixed-size body in one call, skipping the
+    /// `start()` / `write_all()` / `end()` dance for the common case of
+    /// a small, whole-buffer reply.
+    pub fn send(mut self, body: &[u8]) -> IoResult<()> {
+        if !self.headers.has::<common::ContentLength>() &&
+           !self.headers.has::<common::TransferEncoding>() {
+            self.headers.set(common::ContentLength(body.len() as u64));
+        }
+        let mut response = try!(self.start());
+        try!(response.write(body));
+        response.end()
     }
 }
 
@@ -113,18 +230,208 @@ impl<S: NetworkStream> Response<Streaming, S> {
     /// Flushes all writing of a response to the client.
     pub fn end(mut self) -> IoResult<()> {
         debug!("ending");
-        self.flush()
+        let body = self.body.take().expect("response already finished");
+        let mut body = try!(body.end());
+        body.flush()
     }
 }
 
 impl<S: NetworkStream> Writer for Response<Streaming, S> {
     fn write(&mut self, msg: &[u8]) -> IoResult<()> {
         debug!("write {:u} bytes", msg.len());
-        self.body.write(msg)
+        self.body.as_mut().expect("response already finished").write(msg)
     }
 
     fn flush(&mut self) -> IoResult<()> {
-        self.body.flush()
+        self.body.as_mut().expect("response already finished").flush()
+    }
+}
+
+#[unsafe_destructor]
+impl<W: WriteStatus, S: NetworkStream> Drop for Response<W, S> {
+    fn drop(&mut self) {
+        let body = match self.body.take() {
+            Some(body) => body,
+            // Already finished through `end()` or `deconstruct()`.
+            None => return
+        };
+
+        if self.wrote_head {
+            // Dropped mid-body: finish the framing (e.g. the chunked
+            // terminator) and flush, rather than leaving the client
+            // hanging on a truncated response.
+            if let Ok(mut body) = body.end() {
+                let _ = body.flush();
+            }
+        } else {
+            // `start()` was never called: write an empty response so the
+            // client at least gets a valid, if bodyless, reply. The body
+            // is always empty here, so any Content-Length/Transfer-Encoding
+            // the handler set before returning or panicking no longer
+            // describes what's actually being sent -- strip it, and force
+            // Connection: close since we can't satisfy whatever framing
+            // was promised.
+            debug!("response dropped before start(), writing an empty head");
+            strip_body_framing_headers(&mut self.headers);
+            self.headers.set(common::Connection(vec![common::ConnectionOption::Close]));
+            let mut body = body;
+            if write_head(self.version, self.status, &mut self.headers, &mut body).is_ok() {
+                let _ = body.flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{BufferedWriter, IoResult, EndOfFile, standard_error, OtherIoError};
+    use std::io::net::ip::SocketAddr;
+    use std::rc::Rc;
+    use std::str;
+
+    use super::{Response, Fresh};
+    use header::Headers;
+    use header::common::{ContentLength, Connection, ConnectionOption};
+    use method::Method;
+    use net::NetworkStream;
+    use status;
+    use version;
+
+    // A NetworkStream that records everything written to it, so tests can
+    // inspect the bytes a Response actually sent (including whatever Drop
+    // wrote on its way out).
+    struct MockStream(Rc<RefCell<Vec<u8>>>);
+
+    impl MockStream {
+        fn new() -> (MockStream, Rc<RefCell<Vec<u8>>>) {
+            let buf = Rc::new(RefCell::new(Vec::new()));
+            (MockStream(buf.clone()), buf)
+        }
+    }
+
+    impl Clone for MockStream {
+        fn clone(&self) -> MockStream { MockStream(self.0.clone()) }
+    }
+
+    impl Reader for MockStream {
+        fn read(&mut self, _buf: &mut [u8]) -> IoResult<uint> {
+            Err(standard_error(EndOfFile))
+        }
+    }
+
+    impl Writer for MockStream {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.0.borrow_mut().push_all(buf);
+            Ok(())
+        }
+    }
+
+    impl NetworkStream for MockStream {
+        fn peer_name(&mut self) -> IoResult<SocketAddr> {
+            Err(standard_error(OtherIoError))
+        }
+    }
+
+    fn response() -> (Response<Fresh, MockStream>, Rc<RefCell<Vec<u8>>>) {
+        let (stream, buf) = MockStream::new();
+        let response = Response::construct(version::Http11, BufferedWriter::new(stream),
+                                            status::Ok, Headers::new(), Method::Get);
+        (response, buf)
+    }
+
+    fn sent(buf: &Rc<RefCell<Vec<u8>>>) -> String {
+        str::from_utf8(buf.borrow().as_slice()).unwrap().to_string()
+    }
+
+    #[test]
+    fn keep_alive_defaults_to_version() {
+        let (mut r, _) = response();
+        assert!(r.keep_alive());
+        r.version = version::Http10;
+        assert!(!r.keep_alive());
+    }
+
+    #[test]
+    fn keep_alive_honors_an_explicit_connection_header() {
+        let (mut r, _) = response();
+        r.version = version::Http10;
+        r.headers_mut().set(Connection(vec![ConnectionOption::KeepAlive]));
+        assert!(r.keep_alive());
+
+        let (mut r, _) = response();
+        r.headers_mut().set(Connection(vec![ConnectionOption::Close]));
+        assert!(!r.keep_alive());
+    }
+
+    #[test]
+    fn head_response_keeps_content_length_but_sends_no_body() {
+        let (mut r, buf) = response();
+        r.method = Method::Head;
+        r.headers_mut().set(ContentLength(5));
+        let mut streaming = r.start().unwrap();
+        streaming.write(b"hello").unwrap();
+        streaming.end().unwrap();
+
+        let sent = sent(&buf);
+        assert!(sent.contains("Content-Length: 5"));
+        assert!(!sent.contains("hello"));
+        assert!(sent.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn no_content_status_strips_body_framing_headers() {
+        let (mut r, buf) = response();
+        *r.status_mut() = status::NoContent;
+        r.headers_mut().set(ContentLength(5));
+        r.start().unwrap().end().unwrap();
+
+        assert!(!sent(&buf).contains("Content-Length"));
+    }
+
+    #[test]
+    fn dropping_an_unstarted_response_sends_an_empty_head() {
+        let (mut r, buf) = response();
+        r.headers_mut().set(ContentLength(42));
+        drop(r);
+
+        let sent = sent(&buf);
+        assert!(sent.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!sent.contains("Content-Length"));
+        assert!(sent.contains("Connection: close"));
+        assert!(sent.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn dropping_a_sized_response_mid_body_pads_the_remaining_bytes() {
+        let (mut r, buf) = response();
+        r.headers_mut().set(ContentLength(5));
+        let mut streaming = r.start().unwrap();
+        streaming.write(b"ab").unwrap();
+        drop(streaming);
+
+        assert!(sent(&buf).ends_with("ab\0\0\0"));
+    }
+
+    #[test]
+    fn send_sets_content_length_and_writes_the_whole_body() {
+        let (r, buf) = response();
+        r.send(b"hello").unwrap();
+
+        let sent = sent(&buf);
+        assert!(sent.contains("Content-Length: 5"));
+        assert!(sent.ends_with("hello"));
+    }
+
+    #[test]
+    fn send_on_a_head_response_discards_the_body_instead_of_erroring() {
+        let (mut r, buf) = response();
+        r.method = Method::Head;
+        r.send(b"hello").unwrap();
+
+        let sent = sent(&buf);
+        assert!(sent.contains("Content-Length: 5"));
+        assert!(!sent.contains("hello"));
     }
 }
 